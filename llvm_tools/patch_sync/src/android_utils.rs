@@ -1,7 +1,24 @@
 use std::path::Path;
 use std::process::Command;
 
-use anyhow::{bail, ensure, Result};
+use anyhow::{bail, ensure, Context, Result};
+
+const LLVM_ANDROID_REL_PATH: &str = "toolchain/llvm_android";
+
+/// Build a `python3` command rooted in the Android checkout's llvm_android dir, so Python-backed
+/// queries all run with a consistent working directory.
+fn new_android_cmd(android_checkout: &Path, args: &[&str]) -> Result<Command> {
+    let llvm_android_dir = android_checkout.join(LLVM_ANDROID_REL_PATH);
+    ensure!(
+        llvm_android_dir.is_dir(),
+        "{} is not a directory",
+        llvm_android_dir.display()
+    );
+    let mut command = Command::new("python3");
+    command.current_dir(llvm_android_dir);
+    command.args(args);
+    Ok(command)
+}
 
 /// Return the Android checkout's current llvm version.
 ///
@@ -9,19 +26,14 @@ use anyhow::{bail, ensure, Result};
 /// that can't be executed directly. We spawn a Python3 program
 /// to run it and get the result from that.
 pub fn get_android_llvm_version(android_checkout: &Path) -> Result<String> {
-    let mut command = Command::new("python3");
-    let llvm_android_dir = android_checkout.join("toolchain/llvm_android");
-    ensure!(
-        llvm_android_dir.is_dir(),
-        "can't get android llvm version; {} is not a directory",
-        llvm_android_dir.display()
-    );
-    command.current_dir(llvm_android_dir);
-    command.args([
-        "-c",
-        "import android_version; print(android_version.get_svn_revision_number(), end='')",
-    ]);
-    let output = command.output()?;
+    let output = new_android_cmd(
+        android_checkout,
+        &[
+            "-c",
+            "import android_version; print(android_version.get_svn_revision_number(), end='')",
+        ],
+    )?
+    .output()?;
     if !output.status.success() {
         bail!(
             "could not get android llvm version: {}",
@@ -31,3 +43,12 @@ pub fn get_android_llvm_version(android_checkout: &Path) -> Result<String> {
     let out_string = String::from_utf8(output.stdout)?.trim().to_string();
     Ok(out_string)
 }
+
+/// Return the Android checkout's current llvm revision, as a number usable with
+/// `PatchCollection::filter_patches_by_version`/`PatchDictSchema::applies_to_version`.
+pub fn get_android_llvm_version_number(android_checkout: &Path) -> Result<u64> {
+    let version = get_android_llvm_version(android_checkout)?;
+    version
+        .parse()
+        .with_context(|| format!("android llvm version {version:?} is not a number"))
+}
@@ -1,7 +1,9 @@
+mod android_utils;
 mod patch_parsing;
 mod version_control;
 
 use anyhow::{Context, Result};
+use std::cell::Cell;
 use std::path::PathBuf;
 use structopt::StructOpt;
 
@@ -11,7 +13,8 @@ fn main() -> Result<()> {
             cros_checkout_path,
             android_checkout_path,
             sync,
-        } => show_subcmd(cros_checkout_path, android_checkout_path, sync),
+            keep_unmerged,
+        } => show_subcmd(cros_checkout_path, android_checkout_path, sync, keep_unmerged),
         Opt::Transpose {
             cros_checkout_path,
             old_cros_ref,
@@ -21,6 +24,11 @@ fn main() -> Result<()> {
             verbose,
             dry_run,
             no_commit,
+            cros_reviewers,
+            aosp_reviewers,
+            wip,
+            disable_cq,
+            keep_unmerged,
         } => transpose_subcmd(TransposeOpt {
             cros_checkout_path,
             old_cros_ref,
@@ -30,30 +38,54 @@ fn main() -> Result<()> {
             verbose,
             dry_run,
             no_commit,
+            cros_reviewers: parse_emails(&cros_reviewers),
+            aosp_reviewers: parse_emails(&aosp_reviewers),
+            wip,
+            disable_cq,
+            keep_unmerged,
         }),
     }
 }
 
+/// Parse a comma-separated list of emails, trimming whitespace and dropping empty entries
+/// (e.g. from a trailing comma, or an unset flag whose default is an empty string).
+fn parse_emails(raw: &str) -> Vec<String> {
+    raw.split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
 fn show_subcmd(
     cros_checkout_path: PathBuf,
     android_checkout_path: PathBuf,
     sync: bool,
+    keep_unmerged: bool,
 ) -> Result<()> {
-    let ctx = version_control::RepoSetupContext {
-        cros_checkout: cros_checkout_path,
-        android_checkout: android_checkout_path,
-        sync_before: sync,
-    };
+    let ctx = version_control::RepoSetupContext::new(cros_checkout_path, android_checkout_path, sync);
     ctx.setup()?;
     let cros_patches_path = ctx.cros_patches_path();
     let android_patches_path = ctx.android_patches_path();
-    let cur_cros_collection = patch_parsing::PatchCollection::parse_from_file(&cros_patches_path)
-        .context("could not parse cros PATCHES.json")?;
-    let cur_android_collection =
+    let mut cur_cros_collection =
+        patch_parsing::PatchCollection::parse_from_file(&cros_patches_path)
+            .context("could not parse cros PATCHES.json")?;
+    let mut cur_android_collection =
         patch_parsing::PatchCollection::parse_from_file(&android_patches_path)
             .context("could not parse android PATCHES.json")?;
-    let merged = cur_cros_collection.union(&cur_android_collection)?;
-    println!("{}", merged.serialize_patches()?);
+    if !keep_unmerged {
+        // Both repos track the same upstream LLVM revision numbering, so the Android checkout's
+        // resolved revision also serves as the "current LLVM revision" for the CrOS side.
+        let rev = android_utils::get_android_llvm_version_number(&ctx.android_checkout)
+            .context("resolving current LLVM revision")?;
+        // Drop only already-merged patches (`version_range.until` at or below `rev`).
+        cur_cros_collection =
+            cur_cros_collection.filter_patches(|p| p.get_until_version().map_or(true, |u| rev < u));
+        cur_android_collection = cur_android_collection
+            .filter_patches(|p| p.get_until_version().map_or(true, |u| rev < u));
+    }
+    let merged = cur_cros_collection.combined_view(&cur_android_collection, /*keep_unreconciled=*/ true)?;
+    println!("{merged}");
     Ok(())
 }
 
@@ -67,15 +99,41 @@ struct TransposeOpt {
     verbose: bool,
     dry_run: bool,
     no_commit: bool,
+    /// Reviewers for the ChromiumOS overlay CL.
+    cros_reviewers: Vec<String>,
+    /// Reviewers for the llvm_android CL.
+    aosp_reviewers: Vec<String>,
+    /// Send both CLs as work-in-progress instead of out for review.
+    wip: bool,
+    /// Don't opt the ChromiumOS overlay CL into the Commit-Queue.
+    disable_cq: bool,
+    /// Don't drop already-merged patches before transposing.
+    keep_unmerged: bool,
 }
 
 fn transpose_subcmd(args: TransposeOpt) -> Result<()> {
-    let ctx = version_control::RepoSetupContext {
-        cros_checkout: args.cros_checkout_path,
-        android_checkout: args.android_checkout_path,
-        sync_before: args.sync,
-    };
+    let ctx = version_control::RepoSetupContext::new(
+        args.cros_checkout_path,
+        args.android_checkout_path,
+        args.sync,
+    );
     ctx.setup()?;
+
+    // `transpose_write` mutates both checkouts' working trees directly, well before either
+    // `repo_upload` call runs, so guard the whole function: reset both checkouts on any exit
+    // path (an early `?` return, an upload failure, or a panic) unless we make it all the way
+    // to a fully successful commit+upload. The `--dry-run` exit returns before anything is
+    // mutated and `--no-commit` keeps the transposed working tree on purpose, so both of those
+    // exits disarm the guard too.
+    let armed = Cell::new(true);
+    let _cleanup_guard = scopeguard::guard((), |_| {
+        if armed.get() {
+            if let Err(e) = ctx.reset_checkouts() {
+                eprintln!("warning: failed to clean up after a failed transpose: {e:#}");
+            }
+        }
+    });
+
     let cros_patches_path = ctx.cros_patches_path();
     let android_patches_path = ctx.android_patches_path();
 
@@ -107,7 +165,27 @@ fn transpose_subcmd(args: TransposeOpt) -> Result<()> {
         cur_android_collection.subtract(&old_android_collection)?
     };
 
+    // Drop already-merged patches (those whose `version_range.until` is at or below the
+    // current LLVM revision) from what's about to be transposed, unless the caller asked to
+    // keep them around with `--keep-unmerged`.
+    let (new_cros_patches, new_android_patches) = if args.keep_unmerged {
+        (new_cros_patches, new_android_patches)
+    } else {
+        let rev = android_utils::get_android_llvm_version_number(&ctx.android_checkout)
+            .context("resolving current LLVM revision")?;
+        (
+            new_cros_patches.filter_patches(|p| p.get_until_version().map_or(true, |u| rev < u)),
+            new_android_patches.filter_patches(|p| p.get_until_version().map_or(true, |u| rev < u)),
+        )
+    };
+
+    if args.verbose {
+        display_transpose_plan(&new_cros_patches, &new_android_patches);
+    }
+
     if args.dry_run {
+        // Nothing's been mutated yet, so there's nothing to reset.
+        armed.set(false);
         println!("--dry-run specified; skipping modifications");
         return Ok(());
     }
@@ -121,6 +199,9 @@ fn transpose_subcmd(args: TransposeOpt) -> Result<()> {
     }
 
     if args.no_commit {
+        // The caller asked to keep the transposed-but-uncommitted working tree around for
+        // inspection, so disarm rather than reset it away.
+        armed.set(false);
         println!("--no-commit specified; not committing or uploading");
         return Ok(());
     }
@@ -128,16 +209,58 @@ fn transpose_subcmd(args: TransposeOpt) -> Result<()> {
     // Note we want to check if the android patches are empty for CrOS, and
     // vice versa. This is a little counterintuitive.
     if !new_android_patches.is_empty() {
-        ctx.cros_repo_upload()
+        ctx.cros_repo_upload(&args.cros_reviewers, args.wip, /*enable_cq=*/ !args.disable_cq)
+            .inspect_err(|_| {
+                // `repo_upload` already cleans up after itself on failure; this is a defensive
+                // second call, and a no-op if that cleanup already ran.
+                let _ = ctx.cleanup();
+            })
             .context("uploading chromiumos changes")?;
     }
     if !new_cros_patches.is_empty() {
-        ctx.android_repo_upload()
+        ctx.android_repo_upload(&args.aosp_reviewers, args.wip)
+            .inspect_err(|_| {
+                let _ = ctx.cleanup();
+            })
             .context("uploading android changes")?;
     }
+    armed.set(false);
     Ok(())
 }
 
+/// Print a human-readable summary of the patches that are about to be transposed, grouped by
+/// the direction they'll be copied. Used by `--verbose`, so `--dry-run --verbose` together give
+/// a usable preview of what a transpose would do without touching either checkout.
+fn display_transpose_plan(
+    new_cros_patches: &patch_parsing::PatchCollection,
+    new_android_patches: &patch_parsing::PatchCollection,
+) {
+    println!("CrOS -> Android:");
+    display_patches(new_cros_patches);
+    println!("Android -> CrOS:");
+    display_patches(new_android_patches);
+}
+
+fn display_patches(collection: &patch_parsing::PatchCollection) {
+    if collection.patches.is_empty() {
+        println!("  (none)");
+        return;
+    }
+    for p in &collection.patches {
+        let platforms = p.platforms.iter().cloned().collect::<Vec<_>>().join(", ");
+        let from = p
+            .get_from_version()
+            .map_or("any".to_string(), |v| v.to_string());
+        let until = p
+            .get_until_version()
+            .map_or("any".to_string(), |v| v.to_string());
+        println!(
+            "  {} [{platforms}] (from {from}, until {until})",
+            p.rel_patch_path
+        );
+    }
+}
+
 #[derive(Debug, structopt::StructOpt)]
 #[structopt(name = "patch_sync", about = "A pipeline for syncing the patch code")]
 enum Opt {
@@ -150,6 +273,9 @@ enum Opt {
         android_checkout_path: PathBuf,
         #[structopt(short, long)]
         sync: bool,
+        /// Don't drop already-merged patches from the combined view.
+        #[structopt(long)]
+        keep_unmerged: bool,
     },
     /// Transpose patches from two PATCHES.json files
     /// to each other.
@@ -187,5 +313,27 @@ enum Opt {
         /// Implies `--no-upload`.
         #[structopt(long)]
         no_commit: bool,
+
+        /// Comma-separated list of reviewer emails for the ChromiumOS overlay CL.
+        #[structopt(long = "cros-reviewers", default_value = "")]
+        cros_reviewers: String,
+
+        /// Comma-separated list of reviewer emails for the llvm_android CL.
+        #[structopt(long = "aosp-reviewers", default_value = "")]
+        aosp_reviewers: String,
+
+        /// Send both CLs up as work-in-progress instead of out for review. Useful for testing
+        /// the upload path without emailing reviewers.
+        #[structopt(long)]
+        wip: bool,
+
+        /// Don't opt the ChromiumOS overlay CL into the Commit-Queue.
+        #[structopt(long)]
+        disable_cq: bool,
+
+        /// Don't drop already-merged patches before transposing, so every still-relevant patch
+        /// is synced even if it's already landed upstream on one side.
+        #[structopt(long)]
+        keep_unmerged: bool,
     },
 }
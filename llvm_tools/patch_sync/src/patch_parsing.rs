@@ -19,6 +19,14 @@ pub struct PatchDictSchema {
     pub platforms: BTreeSet<String>,
     pub rel_patch_path: String,
     pub version_range: Option<VersionRange>,
+    /// Deprecated alternative to `version_range.from`, migrated into it on parse so it never
+    /// appears in freshly-serialized output.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    start_version: Option<u64>,
+    /// Deprecated alternative to `version_range.until`, migrated into it on parse so it never
+    /// appears in freshly-serialized output.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    end_version: Option<u64>,
 }
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize, Deserialize)]
@@ -38,32 +46,90 @@ impl PatchDictSchema {
     pub fn get_until_version(&self) -> Option<u64> {
         self.version_range.and_then(|x| x.until)
     }
+
+    /// Return whether this patch applies to the given LLVM revision.
+    ///
+    /// Uses half-open interval semantics (`from <= rev < until`); a missing `version_range`, or
+    /// a missing bound within one, means there's no constraint on that side.
+    pub fn applies_to_version(&self, rev: u64) -> bool {
+        match self.version_range {
+            Some(range) => {
+                range.from.map_or(true, |from| rev >= from)
+                    && range.until.map_or(true, |until| rev < until)
+            }
+            None => true,
+        }
+    }
+
+    /// Fold the deprecated `start_version`/`end_version` fields (if present) into
+    /// `version_range`, clearing them so they never round-trip back out through
+    /// `serialize_patches`.
+    fn migrate_legacy_version_range(&mut self) -> Result<()> {
+        if self.start_version.is_none() && self.end_version.is_none() {
+            return Ok(());
+        }
+        let legacy_range = VersionRange {
+            from: self.start_version,
+            until: self.end_version,
+        };
+        match self.version_range {
+            Some(existing) if existing != legacy_range => {
+                return Err(anyhow!(
+                    "patch {} has version_range {:?} that disagrees with its legacy \
+                     start_version/end_version ({:?})",
+                    self.rel_patch_path,
+                    existing,
+                    legacy_range,
+                ));
+            }
+            _ => self.version_range = Some(legacy_range),
+        }
+        self.start_version = None;
+        self.end_version = None;
+        Ok(())
+    }
 }
 
+/// Default indentation width used when a file's existing indentation can't be detected (e.g.
+/// it's empty or single-line).
+const DEFAULT_INDENT_LEN: usize = 4;
+
 /// Struct to keep track of patches and their relative paths.
 #[derive(Debug, Clone)]
 pub struct PatchCollection {
     pub patches: Vec<PatchDictSchema>,
     pub workdir: PathBuf,
+    /// Width (in spaces) of the indentation used by the file this collection was parsed from.
+    /// Reused by `serialize_patches` so re-serializing an existing file doesn't rewrite every
+    /// line just because it used a different indent width than ours.
+    pub indent_len: usize,
 }
 
 impl PatchCollection {
     /// Create a `PatchCollection` from a PATCHES.
     pub fn parse_from_file(json_file: &Path) -> Result<Self> {
-        Ok(Self {
-            patches: serde_json::from_reader(File::open(json_file)?)?,
-            workdir: json_file
+        let contents = std::fs::read_to_string(json_file)
+            .with_context(|| format!("reading {}", json_file.display()))?;
+        Self::parse_from_str(
+            json_file
                 .parent()
                 .ok_or_else(|| anyhow!("failed to get json_file parent"))?
                 .to_path_buf(),
-        })
+            &contents,
+        )
     }
 
     /// Create a `PatchCollection` from a string literal and a workdir.
     pub fn parse_from_str(workdir: PathBuf, contents: &str) -> Result<Self> {
+        let mut patches: Vec<PatchDictSchema> =
+            serde_json::from_str(contents).context("parsing from str")?;
+        for p in &mut patches {
+            p.migrate_legacy_version_range()?;
+        }
         Ok(Self {
-            patches: serde_json::from_str(contents).context("parsing from str")?,
+            patches,
             workdir,
+            indent_len: detect_indent_len(contents),
         })
     }
 
@@ -72,14 +138,21 @@ impl PatchCollection {
         Self {
             patches: self.patches.iter().cloned().filter(f).collect(),
             workdir: self.workdir.clone(),
+            indent_len: self.indent_len,
         }
     }
 
+    /// Copy this collection with only the patches that apply to the given LLVM revision.
+    pub fn filter_patches_by_version(&self, rev: u64) -> Self {
+        self.filter_patches(|p| p.applies_to_version(rev))
+    }
+
     /// Map over the patches.
     pub fn map_patches(&self, f: impl FnMut(&PatchDictSchema) -> PatchDictSchema) -> Self {
         Self {
             patches: self.patches.iter().map(f).collect(),
             workdir: self.workdir.clone(),
+            indent_len: self.indent_len,
         }
     }
 
@@ -91,37 +164,111 @@ impl PatchCollection {
     /// Compute the set-set subtraction, returning a new `PatchCollection` which
     /// keeps the minuend's workdir.
     pub fn subtract(&self, subtrahend: &Self) -> Result<Self> {
-        let mut new_patches = Vec::new();
-        // This is O(n^2) when it could be much faster, but n is always going to be less
-        // than 1k and speed is not important here.
-        for our_patch in &self.patches {
-            let found_in_sub = subtrahend.patches.iter().any(|sub_patch| {
-                let hash1 = subtrahend
-                    .hash_from_rel_patch(sub_patch)
-                    .expect("getting hash from subtrahend patch");
-                let hash2 = self
-                    .hash_from_rel_patch(our_patch)
-                    .expect("getting hash from our patch");
-                hash1 == hash2
-            });
-            if !found_in_sub {
-                new_patches.push(our_patch.clone());
-            }
-        }
+        let our_hashes = self.hash_index()?;
+        let subtrahend_hashes: BTreeSet<String> =
+            subtrahend.hash_index()?.into_values().collect();
+        let new_patches = self
+            .patches
+            .iter()
+            .filter(|our_patch| !subtrahend_hashes.contains(&our_hashes[&our_patch.rel_patch_path]))
+            .cloned()
+            .collect();
         Ok(Self {
             patches: new_patches,
             workdir: self.workdir.clone(),
+            indent_len: self.indent_len,
         })
     }
 
     pub fn union(&self, other: &Self) -> Result<Self> {
+        let our_hashes = self.hash_index()?;
+        let their_hashes = other.hash_index()?;
         self.union_helper(
             other,
-            |p| self.hash_from_rel_patch(p),
-            |p| other.hash_from_rel_patch(p),
+            |p| Ok(our_hashes[&p.rel_patch_path].clone()),
+            |p| Ok(their_hashes[&p.rel_patch_path].clone()),
         )
     }
 
+    /// Build the merged "source of truth" view across this collection and `other`, for display
+    /// rather than for writing back out to a PATCHES.json.
+    ///
+    /// This is `union` (which already merges platform sets and preserves version ranges) over
+    /// only the patches whose files actually exist on disk, since a missing file can't be
+    /// hashed. When `keep_unreconciled` is false, that's the whole result: patches whose files
+    /// are absent are dropped, so the output reflects only patches actually present. When true,
+    /// those absent-file patches are appended unmerged, which is useful for auditing patches
+    /// that haven't been copied over yet.
+    ///
+    /// Unlike `union`, the result isn't a `PatchCollection`: its patches are sourced from two
+    /// different checkouts (`self` and `other`), so there's no single `workdir` that all of
+    /// their `rel_patch_path`s resolve against. `CombinedView` keeps each patch paired with
+    /// the workdir it actually came from instead.
+    ///
+    /// `keep_unreconciled` is unrelated to the CLI's `--keep-unmerged` flag: that flag controls
+    /// whether already-merged-upstream patches get filtered out *before* patches ever reach this
+    /// method, based on version range rather than file presence.
+    pub fn combined_view(&self, other: &Self, keep_unreconciled: bool) -> Result<CombinedView> {
+        let our_present = self.filter_patches(|p| self.patch_exists(p));
+        let their_present = other.filter_patches(|p| other.patch_exists(p));
+        let merged = our_present.union(&their_present)?;
+
+        let mut patches: Vec<CombinedPatch> = merged
+            .patches
+            .into_iter()
+            .enumerate()
+            .map(|(i, p)| {
+                // `union_helper` first pushes exactly one entry for each of `our_present`'s
+                // patches, keeping this side's `rel_patch_path` whether or not it matched one of
+                // `other`'s patches by content hash, and then appends `other`'s unmatched patches
+                // at their own paths. So an entry's position tells us which side's workdir to
+                // resolve it against.
+                let workdir = if i < our_present.patches.len() {
+                    self.workdir.clone()
+                } else {
+                    other.workdir.clone()
+                };
+                CombinedPatch { patch: p, workdir }
+            })
+            .collect();
+        if keep_unreconciled {
+            patches.extend(
+                self.patches
+                    .iter()
+                    .filter(|p| !self.patch_exists(p))
+                    .cloned()
+                    .map(|patch| CombinedPatch {
+                        patch,
+                        workdir: self.workdir.clone(),
+                    }),
+            );
+            patches.extend(
+                other
+                    .patches
+                    .iter()
+                    .filter(|p| !other.patch_exists(p))
+                    .cloned()
+                    .map(|patch| CombinedPatch {
+                        patch,
+                        workdir: other.workdir.clone(),
+                    }),
+            );
+        }
+        Ok(CombinedView { patches })
+    }
+
+    /// Compute every patch's content hash exactly once, keyed by its `rel_patch_path`.
+    ///
+    /// `subtract`/`union` used to re-hash the same file on every comparison they made,
+    /// which is O(n^2) in the number of patches. Hashing each patch a single time up front
+    /// and looking up the result instead keeps that work linear.
+    fn hash_index(&self) -> Result<BTreeMap<String, String>> {
+        self.patches
+            .iter()
+            .map(|p| Ok((p.rel_patch_path.clone(), self.hash_from_rel_patch(p)?)))
+            .collect()
+    }
+
     /// Vec of every PatchDictSchema with differing
     /// version ranges but the same rel_patch_paths.
     fn version_range_diffs(&self, other: &Self) -> Vec<(String, Option<VersionRange>)> {
@@ -174,6 +321,7 @@ impl PatchCollection {
         Self {
             workdir: self.workdir.clone(),
             patches: cloned_patches,
+            indent_len: self.indent_len,
         }
     }
 
@@ -213,6 +361,8 @@ impl PatchCollection {
                             platforms: new_platforms,
                             metadata: p.metadata.clone(),
                             version_range: p.version_range,
+                            start_version: None,
+                            end_version: None,
                         });
                         // iii.
                         *merged = true;
@@ -237,13 +387,61 @@ impl PatchCollection {
         Ok(Self {
             workdir: self.workdir.clone(),
             patches: combined_patches,
+            indent_len: self.indent_len,
         })
     }
 
     /// Copy all patches from this collection into another existing collection, and write that
     /// to the existing collection's file.
+    ///
+    /// A patch that was renamed or moved on this side (same content as one already in
+    /// `existing_collection`, but a different `rel_patch_path`) updates the existing entry in
+    /// place instead of copying a second physical file.
+    ///
+    /// A patch that already has an entry at the same `rel_patch_path` in `existing_collection`
+    /// (e.g. one that's applicable to both platforms and is being re-transposed after a later
+    /// change) also updates in place, but only to add this side's platforms: the existing
+    /// entry's `version_range` is left untouched, so a patch that's already merged on one
+    /// platform but not the other keeps the scoping that reflects that.
     pub fn transpose_write(&self, existing_collection: &mut Self) -> Result<()> {
+        let renames: BTreeMap<String, String> = existing_collection
+            .find_renames(self)
+            .into_iter()
+            .map(|(old_path, new_path)| (new_path, old_path))
+            .collect();
         for p in &self.patches {
+            if let Some(old_path) = renames.get(&p.rel_patch_path) {
+                if let Some(existing) = existing_collection
+                    .patches
+                    .iter_mut()
+                    .find(|e| &e.rel_patch_path == old_path)
+                {
+                    let original_file_path = self.workdir.join(&p.rel_patch_path);
+                    let new_file_path = existing_collection.workdir.join(&p.rel_patch_path);
+                    copy_create_parents(&original_file_path, &new_file_path)?;
+                    let old_file_path = existing_collection.workdir.join(old_path);
+                    if old_file_path != new_file_path {
+                        std::fs::remove_file(&old_file_path).with_context(|| {
+                            format!("removing stale renamed patch {}", old_file_path.display())
+                        })?;
+                    }
+                    // As with the same-path branch below, merge in this side's platforms but
+                    // keep the existing entry's `version_range`, so a patch that's already
+                    // merged on one platform but not the other keeps the scoping that reflects
+                    // that.
+                    existing.rel_patch_path = p.rel_patch_path.clone();
+                    existing.platforms.extend(p.platforms.iter().cloned());
+                    continue;
+                }
+            }
+            if let Some(existing) = existing_collection
+                .patches
+                .iter_mut()
+                .find(|e| e.rel_patch_path == p.rel_patch_path)
+            {
+                existing.platforms.extend(p.platforms.iter().cloned());
+                continue;
+            }
             let original_file_path = self.workdir.join(&p.rel_patch_path);
             let copy_file_path = existing_collection.workdir.join(&p.rel_patch_path);
             copy_create_parents(&original_file_path, &copy_file_path)?;
@@ -252,6 +450,34 @@ impl PatchCollection {
         existing_collection.write_patches_json("PATCHES.json")
     }
 
+    /// Find patches that are content-identical across `self` and `other` but live at different
+    /// `rel_patch_path`s, i.e. were renamed or moved in one repo but not the other.
+    ///
+    /// Returns (old path, new path) pairs, with the "old" path taken from `self` and the "new"
+    /// path from `other`. Patches whose content can't be hashed (e.g. the file is missing) are
+    /// skipped rather than treated as a rename.
+    pub fn find_renames(&self, other: &Self) -> Vec<(String, String)> {
+        let their_paths_by_hash: BTreeMap<String, String> = other
+            .patches
+            .iter()
+            .filter_map(|p| {
+                other
+                    .hash_from_rel_patch(p)
+                    .ok()
+                    .map(|hash| (hash, p.rel_patch_path.clone()))
+            })
+            .collect();
+        self.patches
+            .iter()
+            .filter_map(|p| {
+                let our_hash = self.hash_from_rel_patch(p).ok()?;
+                let their_path = their_paths_by_hash.get(&our_hash)?;
+                (*their_path != p.rel_patch_path)
+                    .then(|| (p.rel_patch_path.clone(), their_path.clone()))
+            })
+            .collect()
+    }
+
     /// Write out the patch collection contents to a PATCHES.json file.
     fn write_patches_json(&self, filename: &str) -> Result<()> {
         let write_path = self.workdir.join(filename);
@@ -263,10 +489,10 @@ impl PatchCollection {
 
     pub fn serialize_patches(&self) -> Result<String> {
         let mut serialization_buffer = Vec::<u8>::new();
-        // Four spaces to indent json serialization.
+        let indent = vec![b' '; self.indent_len];
         let mut serializer = serde_json::Serializer::with_formatter(
             &mut serialization_buffer,
-            serde_json::ser::PrettyFormatter::with_indent(b"    "),
+            serde_json::ser::PrettyFormatter::with_indent(&indent),
         );
         self.patches
             .serialize(&mut serializer)
@@ -310,6 +536,44 @@ impl std::fmt::Display for PatchCollection {
     }
 }
 
+/// A patch produced by `PatchCollection::combined_view`, paired with the workdir it should be
+/// resolved against.
+///
+/// `combined_view` merges patches sourced from two different checkouts (e.g. CrOS and Android)
+/// into one logical view, so unlike `PatchCollection`, there's no single shared workdir that
+/// every entry's `rel_patch_path` can be joined against.
+pub struct CombinedPatch {
+    pub patch: PatchDictSchema,
+    pub workdir: PathBuf,
+}
+
+/// The result of `PatchCollection::combined_view`. See that method's doc comment.
+pub struct CombinedView {
+    pub patches: Vec<CombinedPatch>,
+}
+
+impl std::fmt::Display for CombinedView {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (i, entry) in self.patches.iter().enumerate() {
+            let title = entry
+                .patch
+                .metadata
+                .as_ref()
+                .and_then(|x| x.get("title"))
+                .and_then(serde_json::Value::as_str)
+                .unwrap_or("[No Title]");
+            let path = entry.workdir.join(&entry.patch.rel_patch_path);
+            writeln!(f, "* {}", title)?;
+            if i == self.patches.len() - 1 {
+                write!(f, "  {}", path.display())?;
+            } else {
+                writeln!(f, "  {}", path.display())?;
+            }
+        }
+        Ok(())
+    }
+}
+
 /// Represents information which changed between now and an old version of a PATCHES.json file.
 pub struct PatchTemporalDiff {
     pub cur_collection: PatchCollection,
@@ -394,10 +658,29 @@ fn hash_from_patch(patch_contents: impl Read) -> Result<String> {
 }
 
 fn hash_from_patch_path(patch: &Path) -> Result<String> {
+    #[cfg(test)]
+    test::HASH_FROM_PATCH_PATH_CALLS.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
     let f = File::open(patch).with_context(|| format!("opening patch file {}", patch.display()))?;
     hash_from_patch(f)
 }
 
+/// Detect the indentation width used by the first nested element of a PATCHES.json's raw text,
+/// so re-serializing it can reuse that width instead of always defaulting to four spaces.
+fn detect_indent_len(text: &str) -> usize {
+    for line in text.lines().skip(1) {
+        let stripped = line.trim_start_matches(' ');
+        let leading = line.len() - stripped.len();
+        if leading > 0 {
+            return leading;
+        }
+        if !stripped.is_empty() {
+            // The first nested line has no leading whitespace at all; nothing to detect.
+            break;
+        }
+    }
+    DEFAULT_INDENT_LEN
+}
+
 /// Copy a file from one path to another, and create any parent
 /// directories along the way.
 fn copy_create_parents(from: &Path, to: &Path) -> Result<()> {
@@ -417,6 +700,13 @@ fn copy_create_parents(from: &Path, to: &Path) -> Result<()> {
 mod test {
 
     use super::*;
+    use rand::prelude::Rng;
+    use std::env;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Counts calls to `hash_from_patch_path`, so tests can assert a patch file is only
+    /// ever read off disk once per collection op, even when it's compared against many others.
+    pub(super) static HASH_FROM_PATCH_PATH_CALLS: AtomicUsize = AtomicUsize::new(0);
 
     /// Test we can extract the hash from patch files.
     #[test]
@@ -453,6 +743,8 @@ mod test {
                 from: Some(0),
                 until: Some(1),
             }),
+            start_version: None,
+            end_version: None,
         };
         let patch2 = PatchDictSchema {
             rel_patch_path: "b".into(),
@@ -465,10 +757,12 @@ mod test {
         };
         let collection1 = PatchCollection {
             workdir: PathBuf::new(),
+            indent_len: 4,
             patches: vec![patch1, patch2],
         };
         let collection2 = PatchCollection {
             workdir: PathBuf::new(),
+            indent_len: 4,
             patches: vec![patch3],
         };
         let union = collection1
@@ -499,13 +793,17 @@ mod test {
                 from: Some(0),
                 until: Some(1),
             }),
+            start_version: None,
+            end_version: None,
         };
         let collection1 = PatchCollection {
             workdir: PathBuf::new(),
+            indent_len: 4,
             patches: vec![patch1.clone()],
         };
         let collection2 = PatchCollection {
             workdir: PathBuf::new(),
+            indent_len: 4,
             patches: vec![patch1],
         };
         let union = collection1
@@ -519,6 +817,319 @@ mod test {
         assert_eq!(union.patches[0].platforms.len(), 0);
     }
 
+    /// Make a patch file with the given contents in `dir`, returning a `PatchDictSchema`
+    /// pointing at it.
+    fn write_patch_file(dir: &Path, rel_patch_path: &str, contents: &str) -> PatchDictSchema {
+        let patch_path = dir.join(rel_patch_path);
+        if let Some(parent) = patch_path.parent() {
+            std::fs::create_dir_all(parent).expect("creating patch file parent dir");
+        }
+        std::fs::write(patch_path, contents).expect("writing test patch file");
+        PatchDictSchema {
+            metadata: None,
+            platforms: Default::default(),
+            rel_patch_path: rel_patch_path.to_string(),
+            version_range: None,
+            start_version: None,
+            end_version: None,
+        }
+    }
+
+    #[test]
+    fn test_subtract_and_union_hash_each_file_at_most_once() {
+        let rng: u32 = rand::thread_rng().gen();
+        let dir = env::temp_dir().join(format!("patch_sync_test_hashing_{}", rng));
+        std::fs::create_dir(&dir).expect("creating temp test dir");
+
+        let ours1 = write_patch_file(&dir, "ours1.patch", "our first patch");
+        let ours2 = write_patch_file(&dir, "ours2.patch", "our second patch");
+        let theirs1 = write_patch_file(&dir, "theirs1.patch", "their first patch");
+        let theirs2 = write_patch_file(&dir, "theirs2.patch", "their second patch");
+
+        let ours = PatchCollection {
+            workdir: dir.clone(),
+            indent_len: 4,
+            patches: vec![ours1, ours2],
+        };
+        let theirs = PatchCollection {
+            workdir: dir.clone(),
+            indent_len: 4,
+            patches: vec![theirs1, theirs2],
+        };
+
+        HASH_FROM_PATCH_PATH_CALLS.store(0, Ordering::SeqCst);
+        ours.subtract(&theirs).expect("computing subtraction");
+        assert_eq!(HASH_FROM_PATCH_PATH_CALLS.load(Ordering::SeqCst), 4);
+
+        HASH_FROM_PATCH_PATH_CALLS.store(0, Ordering::SeqCst);
+        ours.union(&theirs).expect("computing union");
+        assert_eq!(HASH_FROM_PATCH_PATH_CALLS.load(Ordering::SeqCst), 4);
+
+        std::fs::remove_dir_all(&dir).expect("removing temp test dir");
+    }
+
+    #[test]
+    fn test_find_renames() {
+        let rng: u32 = rand::thread_rng().gen();
+        let dir = env::temp_dir().join(format!("patch_sync_test_renames_{}", rng));
+        std::fs::create_dir(&dir).expect("creating temp test dir");
+
+        let old_moved = write_patch_file(&dir, "old/moved.patch", "moved patch contents");
+        let old_unchanged = write_patch_file(&dir, "unchanged.patch", "unchanged patch contents");
+        let new_moved = write_patch_file(&dir, "new/moved.patch", "moved patch contents");
+        let new_unchanged = write_patch_file(&dir, "unchanged.patch", "unchanged patch contents");
+        let new_added = write_patch_file(&dir, "added.patch", "brand new patch contents");
+
+        let old_collection = PatchCollection {
+            workdir: dir.clone(),
+            indent_len: 4,
+            patches: vec![old_moved, old_unchanged],
+        };
+        let new_collection = PatchCollection {
+            workdir: dir.clone(),
+            indent_len: 4,
+            patches: vec![new_moved, new_unchanged, new_added],
+        };
+
+        let renames = old_collection.find_renames(&new_collection);
+        assert_eq!(
+            renames,
+            vec![("old/moved.patch".to_string(), "new/moved.patch".to_string())]
+        );
+
+        std::fs::remove_dir_all(&dir).expect("removing temp test dir");
+    }
+
+    #[test]
+    fn test_transpose_write_updates_renamed_entry_in_place() {
+        let rng: u32 = rand::thread_rng().gen();
+        let new_dir = env::temp_dir().join(format!("patch_sync_test_transpose_new_{}", rng));
+        let existing_dir = env::temp_dir().join(format!("patch_sync_test_transpose_old_{}", rng));
+        std::fs::create_dir(&new_dir).expect("creating temp test dir");
+        std::fs::create_dir(&existing_dir).expect("creating temp test dir");
+
+        let existing_patch = write_patch_file(&existing_dir, "old/moved.patch", "moved contents");
+        let new_patch = write_patch_file(&new_dir, "new/moved.patch", "moved contents");
+
+        let new_collection = PatchCollection {
+            workdir: new_dir.clone(),
+            indent_len: 4,
+            patches: vec![new_patch],
+        };
+        let mut existing_collection = PatchCollection {
+            workdir: existing_dir.clone(),
+            indent_len: 4,
+            patches: vec![existing_patch],
+        };
+
+        new_collection
+            .transpose_write(&mut existing_collection)
+            .expect("transposing renamed patch");
+
+        assert_eq!(existing_collection.patches.len(), 1);
+        assert_eq!(existing_collection.patches[0].rel_patch_path, "new/moved.patch");
+        assert!(existing_dir.join("new/moved.patch").exists());
+        assert!(!existing_dir.join("old/moved.patch").exists());
+
+        std::fs::remove_dir_all(&new_dir).expect("removing temp test dir");
+        std::fs::remove_dir_all(&existing_dir).expect("removing temp test dir");
+    }
+
+    #[test]
+    fn test_combined_view_drops_missing_files_unless_keep_unreconciled() {
+        let rng: u32 = rand::thread_rng().gen();
+        let cros_dir = env::temp_dir().join(format!("patch_sync_test_combined_cros_{}", rng));
+        let android_dir = env::temp_dir().join(format!("patch_sync_test_combined_android_{}", rng));
+        std::fs::create_dir(&cros_dir).expect("creating temp test dir");
+        std::fs::create_dir(&android_dir).expect("creating temp test dir");
+
+        let present = write_patch_file(&cros_dir, "present.patch", "present contents");
+        let missing = PatchDictSchema {
+            metadata: None,
+            platforms: Default::default(),
+            rel_patch_path: "missing.patch".to_string(),
+            version_range: None,
+            start_version: None,
+            end_version: None,
+        };
+        let cros_collection = PatchCollection {
+            workdir: cros_dir.clone(),
+            indent_len: 4,
+            patches: vec![present, missing],
+        };
+        let android_collection = PatchCollection {
+            workdir: android_dir.clone(),
+            indent_len: 4,
+            patches: vec![],
+        };
+
+        let dropped = cros_collection
+            .combined_view(&android_collection, /*keep_unreconciled=*/ false)
+            .expect("computing combined view");
+        assert_eq!(dropped.patches.len(), 1);
+        assert_eq!(dropped.patches[0].patch.rel_patch_path, "present.patch");
+
+        let kept = cros_collection
+            .combined_view(&android_collection, /*keep_unreconciled=*/ true)
+            .expect("computing combined view");
+        assert_eq!(kept.patches.len(), 2);
+
+        std::fs::remove_dir_all(&cros_dir).expect("removing temp test dir");
+        std::fs::remove_dir_all(&android_dir).expect("removing temp test dir");
+    }
+
+    #[test]
+    fn test_combined_view_displays_each_patch_against_its_own_workdir() {
+        let rng: u32 = rand::thread_rng().gen();
+        let cros_dir = env::temp_dir().join(format!("patch_sync_test_combined_display_cros_{}", rng));
+        let android_dir =
+            env::temp_dir().join(format!("patch_sync_test_combined_display_android_{}", rng));
+        std::fs::create_dir(&cros_dir).expect("creating temp test dir");
+        std::fs::create_dir(&android_dir).expect("creating temp test dir");
+
+        let cros_only = write_patch_file(&cros_dir, "cros_only.patch", "cros only contents");
+        let android_only = write_patch_file(&android_dir, "android_only.patch", "android only contents");
+        let cros_collection = PatchCollection {
+            workdir: cros_dir.clone(),
+            indent_len: 4,
+            patches: vec![cros_only],
+        };
+        let android_collection = PatchCollection {
+            workdir: android_dir.clone(),
+            indent_len: 4,
+            patches: vec![android_only],
+        };
+
+        let merged = cros_collection
+            .combined_view(&android_collection, /*keep_unreconciled=*/ true)
+            .expect("computing combined view");
+        let displayed = merged.to_string();
+
+        assert!(
+            displayed.contains(&cros_dir.join("cros_only.patch").display().to_string()),
+            "expected cros-only patch to display under the cros workdir, got: {displayed}"
+        );
+        assert!(
+            displayed.contains(&android_dir.join("android_only.patch").display().to_string()),
+            "expected android-only patch to display under the android workdir, got: {displayed}"
+        );
+
+        std::fs::remove_dir_all(&cros_dir).expect("removing temp test dir");
+        std::fs::remove_dir_all(&android_dir).expect("removing temp test dir");
+    }
+
+    #[test]
+    fn test_applies_to_version() {
+        let unbounded = PatchDictSchema {
+            rel_patch_path: "a".into(),
+            metadata: None,
+            platforms: Default::default(),
+            version_range: None,
+            start_version: None,
+            end_version: None,
+        };
+        assert!(unbounded.applies_to_version(0));
+        assert!(unbounded.applies_to_version(1000));
+
+        let bounded = PatchDictSchema {
+            version_range: Some(VersionRange {
+                from: Some(10),
+                until: Some(20),
+            }),
+            ..unbounded.clone()
+        };
+        assert!(!bounded.applies_to_version(9));
+        assert!(bounded.applies_to_version(10));
+        assert!(bounded.applies_to_version(19));
+        assert!(!bounded.applies_to_version(20));
+
+        let from_only = PatchDictSchema {
+            version_range: Some(VersionRange {
+                from: Some(10),
+                until: None,
+            }),
+            ..unbounded.clone()
+        };
+        assert!(!from_only.applies_to_version(9));
+        assert!(from_only.applies_to_version(1000));
+    }
+
+    #[test]
+    fn test_filter_patches_by_version() {
+        let in_range = PatchDictSchema {
+            rel_patch_path: "a".into(),
+            metadata: None,
+            platforms: Default::default(),
+            version_range: Some(VersionRange {
+                from: Some(10),
+                until: Some(20),
+            }),
+            start_version: None,
+            end_version: None,
+        };
+        let out_of_range = PatchDictSchema {
+            rel_patch_path: "b".into(),
+            version_range: Some(VersionRange {
+                from: Some(20),
+                until: None,
+            }),
+            ..in_range.clone()
+        };
+        let collection = PatchCollection {
+            workdir: PathBuf::new(),
+            indent_len: 4,
+            patches: vec![in_range, out_of_range],
+        };
+        let filtered = collection.filter_patches_by_version(15);
+        assert_eq!(filtered.patches.len(), 1);
+        assert_eq!(filtered.patches[0].rel_patch_path, "a");
+    }
+
+    #[test]
+    fn test_preserves_original_indentation() {
+        let two_space_json = "[\n  {\n    \"rel_patch_path\": \"a\"\n  }\n]\n";
+        let collection = PatchCollection::parse_from_str(PathBuf::new(), two_space_json).unwrap();
+        assert_eq!(collection.indent_len, 2);
+        assert!(collection.serialize_patches().unwrap().contains("\n  {"));
+    }
+
+    #[test]
+    fn test_indentation_defaults_to_four_spaces_for_single_line() {
+        let single_line_json = r#"[{"rel_patch_path": "a"}]"#;
+        let collection = PatchCollection::parse_from_str(PathBuf::new(), single_line_json).unwrap();
+        assert_eq!(collection.indent_len, 4);
+    }
+
+    #[test]
+    fn test_legacy_version_range_migrates() {
+        let json = r#"[{
+            "rel_patch_path": "a",
+            "start_version": 12,
+            "end_version": 34
+        }]"#;
+        let collection = PatchCollection::parse_from_str(PathBuf::new(), json).unwrap();
+        assert_eq!(
+            collection.patches[0].version_range,
+            Some(VersionRange {
+                from: Some(12),
+                until: Some(34),
+            })
+        );
+        // The legacy fields shouldn't round-trip back out.
+        assert!(!collection.serialize_patches().unwrap().contains("start_version"));
+    }
+
+    #[test]
+    fn test_legacy_version_range_conflict_errors() {
+        let json = r#"[{
+            "rel_patch_path": "a",
+            "start_version": 12,
+            "end_version": 34,
+            "version_range": {"from": 1, "until": 2}
+        }]"#;
+        assert!(PatchCollection::parse_from_str(PathBuf::new(), json).is_err());
+    }
+
     #[test]
     fn test_version_differentials() {
         let fixture = version_range_fixture();
@@ -562,6 +1173,8 @@ mod test {
                 from: Some(0),
                 until: Some(1),
             }),
+            start_version: None,
+            end_version: None,
         };
         let patch1_updated = PatchDictSchema {
             version_range: Some(VersionRange {
@@ -576,14 +1189,17 @@ mod test {
         };
         let collection1 = PatchCollection {
             workdir: PathBuf::new(),
+            indent_len: 4,
             patches: vec![patch1, patch2.clone()],
         };
         let collection2 = PatchCollection {
             workdir: PathBuf::new(),
+            indent_len: 4,
             patches: vec![patch1_updated, patch2.clone()],
         };
         let collection3 = PatchCollection {
             workdir: PathBuf::new(),
+            indent_len: 4,
             patches: vec![patch2],
         };
         vec![collection1, collection2, collection3]
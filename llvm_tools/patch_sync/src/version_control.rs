@@ -1,5 +1,6 @@
 use anyhow::{anyhow, bail, ensure, Context, Result};
 use regex::Regex;
+use std::cell::{Cell, RefCell};
 use std::ffi::OsStr;
 use std::fs;
 use std::path::{Path, PathBuf};
@@ -8,6 +9,12 @@ use std::process::{Command, Output};
 const CHROMIUMOS_OVERLAY_REL_PATH: &str = "src/third_party/chromiumos-overlay";
 const ANDROID_LLVM_REL_PATH: &str = "toolchain/llvm_android";
 
+/// ChromiumOS overlay's default development branch.
+const CROS_MAIN_BRANCH: &str = "main";
+/// Android llvm_android's default development branch. Historically named
+/// differently than ChromiumOS's.
+const ANDROID_MAIN_BRANCH: &str = "master";
+
 /// Context struct to keep track of both Chromium OS and Android checkouts.
 #[derive(Debug)]
 pub struct RepoSetupContext {
@@ -15,18 +22,79 @@ pub struct RepoSetupContext {
     pub android_checkout: PathBuf,
     /// Run `repo sync` before doing any comparisons.
     pub sync_before: bool,
+    /// Tracking branch to check out in the ChromiumOS overlay before syncing.
+    pub cros_main_branch: String,
+    /// Tracking branch to check out in llvm_android before syncing.
+    pub android_main_branch: String,
+    /// State needed to undo a `repo_upload` call that's currently in progress.
+    /// `None` whenever no upload is mid-flight. Consulted by `cleanup`.
+    pending_cleanup: RefCell<Option<UploadState>>,
+}
+
+/// Everything needed to put a git dir back the way `repo_upload` found it.
+#[derive(Debug)]
+struct UploadState {
+    /// The repo root that `repo start`/`repo abandon` must be run from.
+    repo_root: PathBuf,
+    /// The git dir that was mutated (e.g. the ChromiumOS overlay checkout).
+    git_path: PathBuf,
+    /// The project path passed to `repo start`/`repo abandon`.
+    git_wd: String,
+    /// The branch `git_path` was on before `repo_upload` started mutating it.
+    original_branch: String,
+}
+
+/// How `repo_upload` should vote on CrOS's Commit-Queue label, if at all.
+///
+/// Android has no equivalent label, so `android_repo_upload` passes `None` rather than threading
+/// a vote through for a label its Gerrit host doesn't recognize.
+#[derive(Clone, Copy)]
+enum CqVote {
+    Enable,
+    Disable,
 }
 
 impl RepoSetupContext {
+    pub fn new(cros_checkout: PathBuf, android_checkout: PathBuf, sync_before: bool) -> Self {
+        Self {
+            cros_checkout,
+            android_checkout,
+            sync_before,
+            cros_main_branch: CROS_MAIN_BRANCH.to_string(),
+            android_main_branch: ANDROID_MAIN_BRANCH.to_string(),
+            pending_cleanup: RefCell::new(None),
+        }
+    }
+
     pub fn setup(&self) -> Result<()> {
         if self.sync_before {
+            // A previous run may have left either checkout on the temporary
+            // `patch_sync_branch`; make sure we're syncing the main tracking branch instead.
+            git_cd_cmd(
+                self.cros_patches_path().parent().unwrap(),
+                &["checkout", &self.cros_main_branch],
+            )?;
+            git_cd_cmd(
+                self.android_patches_path().parent().unwrap(),
+                &["checkout", &self.android_main_branch],
+            )?;
             repo_cd_cmd(&self.cros_checkout, &["sync", CHROMIUMOS_OVERLAY_REL_PATH])?;
             repo_cd_cmd(&self.android_checkout, &["sync", ANDROID_LLVM_REL_PATH])?;
         }
         Ok(())
     }
 
-    pub fn cros_repo_upload(&self) -> Result<()> {
+    /// Upload the ChromiumOS side's changes for review.
+    ///
+    /// `reviewers` are added as reviewers via `--re=`. When `wip` is set, the upload is sent as
+    /// work-in-progress and `enable_cq` is ignored; otherwise `enable_cq` opts the change into
+    /// CrOS's Commit-Queue on upload.
+    pub fn cros_repo_upload<S: AsRef<str>>(
+        &self,
+        reviewers: &[S],
+        wip: bool,
+        enable_cq: bool,
+    ) -> Result<()> {
         let llvm_dir = self
             .cros_checkout
             .join(&CHROMIUMOS_OVERLAY_REL_PATH)
@@ -37,33 +105,157 @@ impl RepoSetupContext {
             llvm_dir.display()
         );
         Self::rev_bump_llvm(&llvm_dir)?;
-        Self::repo_upload(
+        self.repo_upload(
             &self.cros_checkout,
             CHROMIUMOS_OVERLAY_REL_PATH,
             &Self::build_commit_msg("android", "chromiumos", "BUG=None\nTEST=CQ"),
+            reviewers,
+            wip,
+            Some(if enable_cq {
+                CqVote::Enable
+            } else {
+                CqVote::Disable
+            }),
         )
     }
 
-    pub fn android_repo_upload(&self) -> Result<()> {
-        Self::repo_upload(
+    /// Upload the Android side's changes for review.
+    ///
+    /// `reviewers` are added as reviewers via `--re=`. When `wip` is set, the upload is sent as
+    /// work-in-progress instead of going out for review.
+    pub fn android_repo_upload<S: AsRef<str>>(&self, reviewers: &[S], wip: bool) -> Result<()> {
+        self.repo_upload(
             &self.android_checkout,
             ANDROID_LLVM_REL_PATH,
             &Self::build_commit_msg("chromiumos", "android", "Test: N/A"),
+            reviewers,
+            wip,
+            // Android has no equivalent of CrOS's Commit-Queue to opt into.
+            None,
         )
     }
 
-    fn repo_upload(path: &Path, git_wd: &str, commit_msg: &str) -> Result<()> {
-        // TODO(ajordanr): Need to clean up if there's any failures during upload.
+    fn repo_upload<S: AsRef<str>>(
+        &self,
+        path: &Path,
+        git_wd: &str,
+        commit_msg: &str,
+        reviewers: &[S],
+        wip: bool,
+        cq_vote: Option<CqVote>,
+    ) -> Result<()> {
         let git_path = &path.join(&git_wd);
         ensure!(
             git_path.is_dir(),
             "git_path {} is not a directory",
             git_path.display()
         );
+
+        self.pending_cleanup.replace(Some(UploadState {
+            repo_root: path.to_path_buf(),
+            git_path: git_path.clone(),
+            git_wd: git_wd.to_string(),
+            original_branch: current_branch(git_path)?,
+        }));
+
+        // If anything below fails (including via an early return from `?`), put the checkout
+        // back the way we found it: drop `patch_sync_branch`, undo the rev bump and any staged
+        // changes, and go back to the branch we started on. Disarmed only once the upload fully
+        // succeeds, so a half-finished upload never leaves the tree dirty.
+        let armed = Cell::new(true);
+        let _cleanup_guard = scopeguard::guard((), |_| {
+            if armed.get() {
+                if let Err(e) = self.cleanup() {
+                    eprintln!("warning: failed to clean up after a failed upload: {e:#}");
+                }
+            }
+        });
+
         repo_cd_cmd(path, &["start", "patch_sync_branch", git_wd])?;
         git_cd_cmd(git_path, &["add", "."])?;
         git_cd_cmd(git_path, &["commit", "-m", commit_msg])?;
-        repo_cd_cmd(path, &["upload", "-y", "--verify", git_wd])?;
+
+        let mut upload_args = vec!["upload".to_string(), "-y".to_string(), "--verify".to_string()];
+        if wip {
+            // Sending as work-in-progress skips review entirely, so CQ can't apply either.
+            upload_args.push("--wip".to_string());
+        } else if let Some(vote) = cq_vote {
+            upload_args.push("-o".to_string());
+            upload_args.push(
+                match vote {
+                    // Explicitly disable CQ rather than just omitting +2, so a Gerrit-side
+                    // default label vote can't sneak the CL onto the Commit-Queue anyway.
+                    CqVote::Enable => "l=Commit-Queue+2",
+                    CqVote::Disable => "l=Commit-Queue+0",
+                }
+                .to_string(),
+            );
+        }
+        if !reviewers.is_empty() {
+            let reviewers = reviewers.iter().map(|r| r.as_ref()).collect::<Vec<_>>().join(",");
+            upload_args.push(format!("--re={reviewers}"));
+        }
+        upload_args.push(git_wd.to_string());
+        repo_cd_cmd(path, &upload_args)?;
+
+        armed.set(false);
+        self.pending_cleanup.take();
+        Ok(())
+    }
+
+    /// Undo whatever `repo_upload` has done so far, if an upload is currently mid-flight.
+    ///
+    /// This is a no-op if no upload is in progress (e.g. because it already succeeded, or
+    /// `cleanup` already ran once). Safe to call again from `main` after a `repo_upload` failure,
+    /// in addition to the automatic cleanup that already runs when `repo_upload` returns early.
+    pub fn cleanup(&self) -> Result<()> {
+        let state = match self.pending_cleanup.take() {
+            Some(state) => state,
+            None => return Ok(()),
+        };
+        git_cd_cmd(&state.git_path, &["reset", "--hard"])?;
+        git_cd_cmd(&state.git_path, &["checkout", &state.original_branch])?;
+        repo_cd_cmd(&state.repo_root, &["abandon", "patch_sync_branch", &state.git_wd])?;
+        Ok(())
+    }
+
+    /// Reset both checkouts to a clean state: discard any uncommitted working-tree changes,
+    /// tracked or untracked (e.g. `transpose_write`'s PATCHES.json edits and copied patch
+    /// files), and drop a local `patch_sync_branch` if one was left behind.
+    ///
+    /// Unlike `cleanup`, this doesn't rely on `repo_upload` having been called first, so it's
+    /// meant to guard the whole `transpose_subcmd` flow rather than a single upload: transpose
+    /// mutates both working trees directly, before either `repo_upload` call runs.
+    pub fn reset_checkouts(&self) -> Result<()> {
+        // Run both resets unconditionally instead of short-circuiting on the first error: a
+        // failure resetting the CrOS checkout shouldn't leave the Android checkout's dirty
+        // working tree untouched, and vice versa.
+        let cros_result = self.reset_checkout(
+            &self.cros_checkout,
+            CHROMIUMOS_OVERLAY_REL_PATH,
+            &self.cros_main_branch,
+        );
+        let android_result = self.reset_checkout(
+            &self.android_checkout,
+            ANDROID_LLVM_REL_PATH,
+            &self.android_main_branch,
+        );
+        cros_result.context("resetting the cros checkout")?;
+        android_result.context("resetting the android checkout")?;
+        Ok(())
+    }
+
+    fn reset_checkout(&self, repo_root: &Path, git_wd: &str, main_branch: &str) -> Result<()> {
+        let git_path = repo_root.join(git_wd);
+        if !git_path.is_dir() {
+            return Ok(());
+        }
+        git_cd_cmd(&git_path, &["reset", "--hard"])?;
+        git_cd_cmd(&git_path, &["clean", "-fd"])?;
+        // Best-effort: there may be no patch_sync_branch to abandon, or we may already be on
+        // `main_branch`. Neither is worth failing the whole reset over.
+        let _ = git_cd_cmd(&git_path, &["checkout", main_branch]);
+        let _ = repo_cd_cmd(repo_root, &["abandon", "patch_sync_branch", git_wd]);
         Ok(())
     }
 
@@ -173,15 +365,77 @@ fn find_ebuild(dir: &Path) -> Result<PathBuf> {
     bail!("could not find ebuild")
 }
 
+/// Return the name of the branch currently checked out in `git_path`.
+fn current_branch(git_path: &Path) -> Result<String> {
+    let output = git_cd_cmd(git_path, &["rev-parse", "--abbrev-ref", "HEAD"])?;
+    String::from_utf8(output.stdout)
+        .map(|s| s.trim().to_string())
+        .with_context(|| format!("converting current branch of {} to UTF-8", git_path.display()))
+}
+
+/// A wrapped `git`/`repo` command that exited unsuccessfully.
+///
+/// Captures enough to diagnose the failure without re-running the command by hand: the full
+/// argv, the working directory, the exit status, and (when available) the stderr output.
+#[derive(Debug)]
+struct CommandError {
+    program: &'static str,
+    args: Vec<String>,
+    cwd: PathBuf,
+    status: std::process::ExitStatus,
+    /// `git_cd_cmd` captures output and so always populates this; `repo_cd_cmd` streams to the
+    /// terminal instead, so it's always `None` there.
+    stderr: Option<String>,
+}
+
+impl std::fmt::Display for CommandError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "`{} {}` in {} failed with {}",
+            self.program,
+            self.args.join(" "),
+            self.cwd.display(),
+            self.status,
+        )?;
+        if let Some(stderr) = &self.stderr {
+            if !stderr.trim().is_empty() {
+                write!(f, "\nstderr:\n{stderr}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for CommandError {}
+
+fn stringify_args<I, S>(args: I) -> Vec<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    args.into_iter()
+        .map(|s| s.as_ref().to_string_lossy().into_owned())
+        .collect()
+}
+
 /// Run a given git command from inside a specified git dir.
 pub fn git_cd_cmd<I, S>(pwd: &Path, args: I) -> Result<Output>
 where
     I: IntoIterator<Item = S>,
     S: AsRef<OsStr>,
 {
-    let output = Command::new("git").current_dir(&pwd).args(args).output()?;
+    let args = stringify_args(args);
+    let output = Command::new("git").current_dir(pwd).args(&args).output()?;
     if !output.status.success() {
-        bail!("git command failed")
+        return Err(CommandError {
+            program: "git",
+            args,
+            cwd: pwd.to_path_buf(),
+            status: output.status,
+            stderr: Some(String::from_utf8_lossy(&output.stderr).into_owned()),
+        }
+        .into());
     }
     Ok(output)
 }
@@ -191,9 +445,17 @@ where
     I: IntoIterator<Item = S>,
     S: AsRef<OsStr>,
 {
-    let status = Command::new("repo").current_dir(&pwd).args(args).status()?;
+    let args = stringify_args(args);
+    let status = Command::new("repo").current_dir(pwd).args(&args).status()?;
     if !status.success() {
-        bail!("repo command failed")
+        return Err(CommandError {
+            program: "repo",
+            args,
+            cwd: pwd.to_path_buf(),
+            status,
+            stderr: None,
+        }
+        .into());
     }
     Ok(())
 }
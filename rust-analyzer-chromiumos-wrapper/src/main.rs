@@ -13,27 +13,39 @@ use std::thread;
 
 use anyhow::{anyhow, bail, Context, Result};
 use lazy_static::lazy_static;
-use log::trace;
+use log::{trace, warn};
+use percent_encoding::{percent_decode_str, utf8_percent_encode, AsciiSet, NON_ALPHANUMERIC};
 
 use regex::Regex;
 
 use simplelog::{Config, LevelFilter, WriteLogger};
 
-use serde_json::{from_slice, to_writer, Value};
-
 const CHROOT_SERVER_PATH: &str = "/usr/sbin/rust-analyzer";
 
+const FILE_URI_SCHEME: &str = "file://";
+
+/// Characters that `utf8_percent_encode` must leave alone when re-encoding a `file://` path, on
+/// top of what `NON_ALPHANUMERIC` already allows through.
+const PATH_ENCODE_SET: &AsciiSet = &NON_ALPHANUMERIC
+    .remove(b'/')
+    .remove(b'-')
+    .remove(b'_')
+    .remove(b'.')
+    .remove(b'~')
+    .remove(b':');
+
 fn main() -> Result<()> {
     let args = env::args().skip(1);
-
     let d = env::current_dir()?;
-    let chromiumos_root = match find_chromiumos_root(&d) {
-        Some(x) => x,
-        None => {
-            // It doesn't appear that we're in a chroot. Run the
-            // regular rust-analyzer.
-            bail!(process::Command::new("rust-analyzer").args(args).exec());
-        }
+
+    let bridge: Box<dyn ChrootBridge> = if let Some(root) = ChromiumOsBridge::detect_root(&d) {
+        Box::new(ChromiumOsBridge { root })
+    } else if let Some(root) = AndroidBridge::detect_root(&d) {
+        Box::new(AndroidBridge { root })
+    } else {
+        // It doesn't appear that we're in a ChromiumOS chroot or an Android checkout. Run the
+        // regular rust-analyzer.
+        bail!(process::Command::new("rust-analyzer").args(args).exec());
     };
 
     let args: Vec<String> = args.collect();
@@ -42,11 +54,8 @@ fn main() -> Result<()> {
         // * We just forward the arguments to rust-analyzer and exit.
         // * We don't support the arguments, so we bail.
         // * We still need to do our path translation in the LSP protocol.
-        fn run(args: &[String]) -> Result<()> {
-            bail!(process::Command::new("cros_sdk")
-                .args(["--", "rust-analyzer"])
-                .args(args)
-                .exec());
+        fn run(bridge: &dyn ChrootBridge, args: &[String]) -> Result<()> {
+            bail!(bridge.build_command(args)?.exec());
         }
 
         if args.iter().any(|x| {
@@ -56,13 +65,13 @@ fn main() -> Result<()> {
             )
         }) {
             // With any of these options rust-analyzer will just print something and exit.
-            return run(&args);
+            return run(bridge.as_ref(), &args);
         }
 
         if !args[0].starts_with('-') {
             // It's a subcommand, and seemingly none of these need the path translation
             // rust-analyzer-chromiumos-wrapper provides.
-            return run(&args);
+            return run(bridge.as_ref(), &args);
         }
 
         if args.iter().any(|x| x == "--log-file") {
@@ -74,61 +83,32 @@ fn main() -> Result<()> {
 
     init_log()?;
 
-    // Get the rust sysroot, this is needed to translate filepaths to sysroot
-    // related files, e.g. crate sources.
-    let outside_rust_sysroot = {
-        let output = process::Command::new("rustc")
-            .arg("--print")
-            .arg("sysroot")
-            .output()?;
-        if !output.status.success() {
-            bail!("Unable to find rustc installation outside of sysroot");
-        }
-        std::str::from_utf8(&output.stdout)?.to_owned()
-    };
-    let outside_rust_sysroot = outside_rust_sysroot.trim();
+    let replacement_map = bridge.build_replacement_map()?;
 
-    // The /home path inside the chroot is visible outside through "<chromiumos-root>/out/home".
-    let outside_home: &'static str =
-        Box::leak(format!("{}/out/home", chromiumos_root.display()).into_boxed_str());
-
-    let outside_prefix: &'static str = {
-        let mut path = chromiumos_root
-            .to_str()
-            .ok_or_else(|| anyhow!("Path is not valid UTF-8"))?
-            .to_owned();
-
-        if Some(&b'/') == path.as_bytes().last() {
-            let _ = path.pop();
-        }
-
-        // No need to ever free this memory, so let's get a static reference.
-        Box::leak(path.into_boxed_str())
-    };
-
-    trace!("Found chromiumos root {}", outside_prefix);
-
-    let outside_sysroot_prefix: &'static str =
-        Box::leak(format!("{outside_rust_sysroot}/lib/rustlib").into_boxed_str());
-    let inside_prefix: &'static str = "/mnt/host/source";
-
-    let cmd = "cros_sdk";
-    let all_args = ["--", CHROOT_SERVER_PATH]
-        .into_iter()
-        .chain(args.iter().map(|x| x.as_str()));
-    let mut child = KillOnDrop(run_command(cmd, all_args)?);
+    let mut child = KillOnDrop(spawn_piped(bridge.build_command(&args)?)?);
 
     let mut child_stdin = BufWriter::new(child.0.stdin.take().unwrap());
     let mut child_stdout = BufReader::new(child.0.stdout.take().unwrap());
-
-    let replacement_map = [
-        (outside_prefix, inside_prefix),
-        (outside_sysroot_prefix, "/usr/lib/rustlib"),
-        (outside_home, "/home"),
-    ];
+    let child_stderr = BufReader::new(child.0.stderr.take().unwrap());
+
+    // Forward the child's stderr to our log file on its own thread, concurrently with the
+    // stdin/stdout streaming below. Otherwise it's inherited straight into the LSP client's
+    // stderr, and if a future change ever pipes it without reading it concurrently, a full pipe
+    // buffer could deadlock us while we're blocked forwarding stdout.
+    let stderr_handle = thread::spawn(move || {
+        for line in child_stderr.lines() {
+            match line {
+                Ok(line) => trace!("[rust-analyzer stderr] {}", line),
+                Err(e) => {
+                    warn!("Error reading rust-analyzer stderr: {}", e);
+                    break;
+                }
+            }
+        }
+    });
 
     let join_handle = {
-        let rm = replacement_map;
+        let rm = replacement_map.clone();
         thread::spawn(move || {
             let mut stdin = io::stdin().lock();
             stream_with_replacement(&mut stdin, &mut child_stdin, &rm)
@@ -137,17 +117,270 @@ fn main() -> Result<()> {
     };
 
     // For the mapping between inside to outside, we just reverse the map.
-    let replacement_map_rev = replacement_map.map(|(k, v)| (v, k));
+    let replacement_map_rev: Vec<(&str, &str)> =
+        replacement_map.iter().map(|&(k, v)| (v, k)).collect();
     let mut stdout = BufWriter::new(io::stdout().lock());
     stream_with_replacement(&mut child_stdout, &mut stdout, &replacement_map_rev)
         .context("Streaming from rust-analyzer into stdout")?;
 
     join_handle.join().unwrap()?;
+    stderr_handle.join().unwrap();
 
     let code = child.0.wait().context("Running rust-analyzer")?.code();
     std::process::exit(code.unwrap_or(127));
 }
 
+/// A detected build environment that needs help launching `rust-analyzer` and translating the
+/// paths it sees back and forth to paths the outside editor can open. ChromiumOS runs
+/// `rust-analyzer` inside a chroot behind a separate mount namespace; Android runs a prebuilt
+/// binary straight out of the checkout.
+trait ChrootBridge {
+    /// Walk upward from `start` looking for markers of this environment's checkout. Returns its
+    /// root on success.
+    fn detect_root(start: &Path) -> Option<PathBuf>
+    where
+        Self: Sized;
+
+    /// Build the command that launches `rust-analyzer` for this checkout, forwarding `args`.
+    fn build_command(&self, args: &[String]) -> Result<process::Command>;
+
+    /// Build the `(outside, inside)` path replacement pairs needed to translate LSP payloads
+    /// between the outside editor and this checkout's `rust-analyzer`.
+    fn build_replacement_map(&self) -> Result<Vec<(&'static str, &'static str)>>;
+}
+
+/// Bridge into a ChromiumOS chroot, via `cros_sdk`.
+struct ChromiumOsBridge {
+    root: PathBuf,
+}
+
+impl ChrootBridge for ChromiumOsBridge {
+    fn detect_root(start: &Path) -> Option<PathBuf> {
+        let mut buf = start.to_path_buf();
+        loop {
+            buf.push(".chroot_lock");
+            if buf.exists() {
+                buf.pop();
+                return Some(buf);
+            }
+            buf.pop();
+            if !buf.pop() {
+                return None;
+            }
+        }
+    }
+
+    fn build_command(&self, args: &[String]) -> Result<process::Command> {
+        let mut cmd = process::Command::new("cros_sdk");
+        cmd.args(["--", CHROOT_SERVER_PATH]).args(args);
+        Ok(cmd)
+    }
+
+    fn build_replacement_map(&self) -> Result<Vec<(&'static str, &'static str)>> {
+        // Get the rust sysroot, this is needed to translate filepaths to sysroot
+        // related files, e.g. crate sources.
+        let outside_rust_sysroot = {
+            let output = process::Command::new("rustc")
+                .arg("--print")
+                .arg("sysroot")
+                .output()?;
+            if !output.status.success() {
+                bail!("Unable to find rustc installation outside of sysroot");
+            }
+            std::str::from_utf8(&output.stdout)?.trim().to_owned()
+        };
+
+        // The /home path inside the chroot is visible outside through "<chromiumos-root>/out/home".
+        let outside_home: &'static str =
+            Box::leak(format!("{}/out/home", self.root.display()).into_boxed_str());
+
+        let outside_prefix: &'static str = {
+            let mut path = self
+                .root
+                .to_str()
+                .ok_or_else(|| anyhow!("Path is not valid UTF-8"))?
+                .to_owned();
+
+            if Some(&b'/') == path.as_bytes().last() {
+                let _ = path.pop();
+            }
+
+            // No need to ever free this memory, so let's get a static reference.
+            Box::leak(path.into_boxed_str())
+        };
+
+        trace!("Found chromiumos root {}", outside_prefix);
+
+        let outside_sysroot_prefix: &'static str =
+            Box::leak(format!("{outside_rust_sysroot}/lib/rustlib").into_boxed_str());
+        let inside_prefix: &'static str = "/mnt/host/source";
+
+        let mut replacement_map: Vec<(&'static str, &'static str)> = vec![
+            (outside_prefix, inside_prefix),
+            (outside_sysroot_prefix, "/usr/lib/rustlib"),
+        ];
+
+        // "go to definition" into a dependency crate resolves to wherever `cargo metadata` says
+        // that crate's source lives, which can be anywhere under the chroot's cargo registry or
+        // a vendored/patched directory under /home. Ask `cargo metadata` for the
+        // workspace-accurate set of package locations and insert those ahead of the generic
+        // `/home` rule below, so `translate_string`'s sequential replacement tries the more
+        // specific, crate-accurate prefix first. This is a nice-to-have, so a failure here
+        // shouldn't stop the wrapper from working with just the static map.
+        match cargo_metadata_replacement_pairs(&self.root, outside_home) {
+            Ok(pairs) => replacement_map.extend(pairs),
+            Err(e) => warn!(
+                "Could not derive a replacement map from `cargo metadata`, continuing with the \
+                 static map: {:#}",
+                e
+            ),
+        }
+
+        // Generic fallback for anything under /home that cargo metadata didn't account for.
+        replacement_map.push((outside_home, "/home"));
+
+        Ok(replacement_map)
+    }
+}
+
+/// Path, relative to an Android checkout's root, of the `toolchain/llvm_android` project. Used
+/// both as a root marker and to locate the `android_version` python helper.
+const ANDROID_LLVM_REL_PATH: &str = "toolchain/llvm_android";
+
+/// Bridge into an Android toolchain checkout, running its prebuilt `rust-analyzer` directly.
+struct AndroidBridge {
+    root: PathBuf,
+}
+
+impl AndroidBridge {
+    /// Host tag used for the prebuilt Rust toolchains checked into `prebuilts/rust`.
+    const PREBUILT_HOST_TAG: &'static str = "linux-x86";
+
+    /// Directory holding the prebuilt `rustc`/`rust-analyzer` binaries matching this checkout's
+    /// pinned LLVM revision.
+    fn prebuilt_rust_dir(&self) -> Result<PathBuf> {
+        let version = get_android_llvm_version(&self.root)?;
+        Ok(self
+            .root
+            .join("prebuilts/rust")
+            .join(Self::PREBUILT_HOST_TAG)
+            .join(version))
+    }
+}
+
+impl ChrootBridge for AndroidBridge {
+    fn detect_root(start: &Path) -> Option<PathBuf> {
+        let mut buf = start.to_path_buf();
+        loop {
+            if buf.join(".repo").is_dir() && buf.join(ANDROID_LLVM_REL_PATH).is_dir() {
+                return Some(buf);
+            }
+            if !buf.pop() {
+                return None;
+            }
+        }
+    }
+
+    fn build_command(&self, args: &[String]) -> Result<process::Command> {
+        let rust_analyzer = self.prebuilt_rust_dir()?.join("bin/rust-analyzer");
+        let mut cmd = process::Command::new(rust_analyzer);
+        cmd.args(args);
+        Ok(cmd)
+    }
+
+    fn build_replacement_map(&self) -> Result<Vec<(&'static str, &'static str)>> {
+        // Unlike ChromiumOS, an Android checkout's rust-analyzer isn't launched inside a separate
+        // mount namespace: it's the prebuilt binary under this very checkout, invoked directly, so
+        // the paths it reports already match what the outside editor sees. Nothing to translate.
+        Ok(Vec::new())
+    }
+}
+
+/// Return the Android checkout's current LLVM version, so the matching prebuilt toolchain under
+/// `prebuilts/rust` can be selected.
+///
+/// This uses android_version.get_svn_revision_number, a python function that can't be executed
+/// directly. We spawn a python3 program to run it and get the result from that.
+fn get_android_llvm_version(android_checkout: &Path) -> Result<String> {
+    let llvm_android_dir = android_checkout.join(ANDROID_LLVM_REL_PATH);
+    let output = process::Command::new("python3")
+        .current_dir(&llvm_android_dir)
+        .args([
+            "-c",
+            "import android_version; print(android_version.get_svn_revision_number(), end='')",
+        ])
+        .output()
+        .with_context(|| {
+            format!(
+                "running the android_version helper in {}",
+                llvm_android_dir.display()
+            )
+        })?;
+    if !output.status.success() {
+        bail!(
+            "could not get android llvm version: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    Ok(String::from_utf8(output.stdout)?.trim().to_string())
+}
+
+/// Ask `cargo metadata` (run inside the chroot via `cros_sdk`) for the on-disk location of every
+/// package in the workspace, and translate each one into an `(outside, inside)` replacement pair
+/// alongside the fixed ones in `ChromiumOsBridge::build_replacement_map`, so "go to definition" into a dependency crate under the
+/// chroot's cargo registry or a vendored/patched directory resolves to a path the outside editor
+/// can actually open.
+///
+/// Only packages that live under `/home` inside the chroot are translatable, since that's the only
+/// part of the chroot's filesystem that's bind-mounted out (as `<root>/out/home`); anything else is
+/// skipped.
+fn cargo_metadata_replacement_pairs(
+    chromiumos_root: &Path,
+    outside_home: &str,
+) -> Result<Vec<(&'static str, &'static str)>> {
+    #[derive(serde::Deserialize)]
+    struct Metadata {
+        packages: Vec<Package>,
+    }
+
+    #[derive(serde::Deserialize)]
+    struct Package {
+        manifest_path: PathBuf,
+    }
+
+    let output = process::Command::new("cros_sdk")
+        .current_dir(chromiumos_root)
+        .args(["--", "cargo", "metadata", "--format-version=1"])
+        .output()
+        .context("running `cargo metadata`")?;
+    if !output.status.success() {
+        bail!(
+            "`cargo metadata` failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
+    }
+    let metadata: Metadata =
+        serde_json::from_slice(&output.stdout).context("parsing `cargo metadata` output")?;
+
+    let mut pairs = Vec::new();
+    for package in metadata.packages {
+        let Some(inside_dir) = package.manifest_path.parent() else {
+            continue;
+        };
+        let Ok(rel_to_home) = inside_dir.strip_prefix("/home") else {
+            continue;
+        };
+
+        let inside_dir: &'static str =
+            Box::leak(inside_dir.to_string_lossy().into_owned().into_boxed_str());
+        let outside_dir: &'static str = Box::leak(
+            format!("{outside_home}/{}", rel_to_home.display()).into_boxed_str(),
+        );
+        pairs.push((outside_dir, inside_dir));
+    }
+    Ok(pairs)
+}
+
 fn init_log() -> Result<()> {
     if !cfg!(feature = "no_debug_log") {
         let filename = env::var("RUST_ANALYZER_CHROMIUMOS_WRAPPER_LOG")
@@ -204,57 +437,85 @@ fn read_header<R: BufRead>(r: &mut R, header: &mut Header) -> Result<()> {
     }
 }
 
+/// Translate a single JSON string value, applying `replacement_map` to any path-like prefixes it
+/// contains.
+///
+/// LSP transmits most paths as `file://` URIs, and the path portion of those is percent-encoded
+/// (e.g. a space becomes `%20`), so a plain substring match against an outside prefix like
+/// `/mnt/host/source` never fires. When `s` starts with `file://`, strip the scheme, percent-decode
+/// the path, apply the prefix replacements against the decoded form, then re-encode and reattach
+/// the scheme. Anything else goes through the existing plain-substring path, which also still
+/// handles the server-path regex.
+fn translate_string(s: &str, replacement_map: &[(&str, &str)]) -> String {
+    if let Some(path) = s.strip_prefix(FILE_URI_SCHEME) {
+        let mut decoded = percent_decode_str(path).decode_utf8_lossy().into_owned();
+        for (pattern, replacement) in replacement_map {
+            decoded = decoded.replace(pattern, replacement);
+        }
+        let encoded = utf8_percent_encode(&decoded, PATH_ENCODE_SET);
+        return format!("{FILE_URI_SCHEME}{encoded}");
+    }
+
+    lazy_static! {
+        static ref SERVER_PATH_REGEX: Regex =
+            Regex::new(r".*/rust-analyzer-chromiumos-wrapper$").unwrap();
+    }
+    // Always replace the server path everywhere.
+    // `s.replace` is very likely doing more work than necessary. Probably we only need
+    // to look for the pattern at the beginning of the string.
+    let mut s = SERVER_PATH_REGEX
+        .replace_all(s, CHROOT_SERVER_PATH)
+        .to_string();
+    // Then replace all mappings we get.
+    for (pattern, replacement) in replacement_map {
+        s = s.replace(pattern, replacement);
+    }
+    s
+}
+
+/// Find the end (one past the closing quote) of the JSON string token starting at `bytes[0]`,
+/// which must be `"`. Tracks `\"`/`\\` escapes so an escaped quote doesn't end the token early;
+/// everything else (including `\uXXXX` escapes) is skipped byte-by-byte, since none of those
+/// bytes can themselves be an unescaped quote or backslash.
+fn find_string_token_end(bytes: &[u8]) -> Result<usize> {
+    debug_assert_eq!(bytes.first(), Some(&b'"'));
+    let mut i = 1;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'\\' => i += 2,
+            b'"' => return Ok(i + 1),
+            _ => i += 1,
+        }
+    }
+    bail!("Unterminated JSON string in payload")
+}
+
 /// Extend `dest` with `contents`, replacing any occurrence of patterns in a json string in
 /// `contents` with a replacement.
+///
+/// Unlike a full parse-rewrite-reserialize round trip, this preserves every byte of `contents`
+/// verbatim except inside JSON string tokens, which is both cheaper for large payloads (e.g.
+/// `workspace/symbol` results or big `didChangeWatchedFiles` batches) and avoids needlessly
+/// reformatting the JSON.
 fn replace(contents: &[u8], replacement_map: &[(&str, &str)], dest: &mut Vec<u8>) -> Result<()> {
-    fn map_value(val: Value, replacement_map: &[(&str, &str)]) -> Value {
-        match val {
-            Value::String(s) =>
-            // `s.replace` is very likely doing more work than necessary. Probably we only need
-            // to look for the pattern at the beginning of the string.
-            {
-                lazy_static! {
-                    static ref SERVER_PATH_REGEX: Regex =
-                        Regex::new(r".*/rust-analyzer-chromiumos-wrapper$").unwrap();
-                }
-                // Always replace the server path everywhere.
-                let mut s = SERVER_PATH_REGEX
-                    .replace_all(&s, CHROOT_SERVER_PATH)
-                    .to_string();
-                // Then replace all mappings we get.
-                for (pattern, replacement) in replacement_map {
-                    s = s.replace(pattern, replacement);
-                }
-                Value::String(s.to_string())
-            }
-            Value::Array(mut v) => {
-                for val_ref in v.iter_mut() {
-                    let value = std::mem::replace(val_ref, Value::Null);
-                    *val_ref = map_value(value, replacement_map);
-                }
-                Value::Array(v)
-            }
-            Value::Object(mut map) => {
-                // Surely keys can't be paths.
-                for val_ref in map.values_mut() {
-                    let value = std::mem::replace(val_ref, Value::Null);
-                    *val_ref = map_value(value, replacement_map);
-                }
-                Value::Object(map)
-            }
-            x => x,
+    let mut i = 0;
+    while i < contents.len() {
+        if contents[i] != b'"' {
+            dest.push(contents[i]);
+            i += 1;
+            continue;
         }
-    }
 
-    let init_val: Value = from_slice(contents).with_context(|| match from_utf8(contents) {
-        Err(_) => format!(
-            "JSON parsing content of length {} that's not valid UTF-8",
-            contents.len()
-        ),
-        Ok(s) => format!("JSON parsing content of length {}:\n{}", contents.len(), s),
-    })?;
-    let mapped_val = map_value(init_val, replacement_map);
-    to_writer(dest, &mapped_val)?;
+        let token_len = find_string_token_end(&contents[i..]).with_context(|| {
+            format!("Scanning JSON string token starting at byte {}", i)
+        })?;
+        let token = &contents[i..i + token_len];
+        let decoded: String = serde_json::from_slice(token)
+            .with_context(|| format!("Decoding JSON string token at byte {}", i))?;
+        let translated = translate_string(&decoded, replacement_map);
+        dest.extend_from_slice(serde_json::to_string(&translated)?.as_bytes());
+        i += token_len;
+    }
     Ok(())
 }
 
@@ -303,32 +564,16 @@ fn stream_with_replacement<R: BufRead, W: Write>(
     }
 }
 
-fn run_command<'a, I>(cmd: &'a str, args: I) -> Result<process::Child>
-where
-    I: IntoIterator<Item = &'a str>,
-{
-    Ok(process::Command::new(cmd)
-        .args(args)
+/// Spawn `cmd` with stdin/stdout/stderr all piped, so the caller can stream LSP messages through
+/// it and forward its stderr.
+fn spawn_piped(mut cmd: process::Command) -> Result<process::Child> {
+    Ok(cmd
         .stdin(process::Stdio::piped())
         .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
         .spawn()?)
 }
 
-fn find_chromiumos_root(start: &Path) -> Option<PathBuf> {
-    let mut buf = start.to_path_buf();
-    loop {
-        buf.push(".chroot_lock");
-        if buf.exists() {
-            buf.pop();
-            return Some(buf);
-        }
-        buf.pop();
-        if !buf.pop() {
-            return None;
-        }
-    }
-}
-
 struct KillOnDrop(Child);
 
 impl Drop for KillOnDrop {
@@ -340,6 +585,7 @@ impl Drop for KillOnDrop {
 #[cfg(test)]
 mod test {
     use super::*;
+    use serde_json::{from_slice, Value};
 
     fn test_stream_with_replacement(
         read: &str,
@@ -353,15 +599,19 @@ mod test {
         // serde_json may not format the json output the same as we do, so we can't just compare
         // as strings or slices.
 
-        let (w1, w2) = {
-            let mut split = w.rsplitn(2, |&c| c == b'\n');
-            let w2 = split.next().unwrap();
-            (split.next().unwrap(), w2)
-        };
+        // Split on the blank line ending the header, rather than the last `\n` in the buffer: now
+        // that `replace` preserves the payload's original formatting byte-for-byte outside of
+        // strings, a pretty-printed payload can itself contain newlines.
+        let header_end = w
+            .windows(4)
+            .position(|w| w == b"\r\n\r\n")
+            .expect("missing blank line ending the header")
+            + 4;
+        let (w1, w2) = w.split_at(header_end);
 
         assert_eq!(
             from_utf8(w1)?,
-            format!("Content-Length: {}\r\n\r", w2.len())
+            format!("Content-Length: {}\r\n\r\n", w2.len())
         );
 
         let v1: Value = from_slice(w2)?;
@@ -427,4 +677,78 @@ mod test {
             }"#,
         )
     }
+
+    #[test]
+    fn test_stream_with_replacement_file_uri() -> Result<()> {
+        test_stream_with_replacement(
+            r#"{
+                "rootUri": "file:///mnt/host/source/a%20b/foo.rs",
+                "other": "unaffected"
+            }"#,
+            &[("/mnt/host/source", "/home/user/chromiumos")],
+            r#"{
+                "rootUri": "file:///home/user/chromiumos/a%20b/foo.rs",
+                "other": "unaffected"
+            }"#,
+        )
+    }
+
+    #[test]
+    fn test_stream_with_replacement_file_uri_unicode() -> Result<()> {
+        test_stream_with_replacement(
+            r#"{
+                "uri": "file:///mnt/host/source/caf%C3%A9/lib.rs"
+            }"#,
+            &[("/mnt/host/source", "/home/user/chromiumos")],
+            r#"{
+                "uri": "file:///home/user/chromiumos/caf%C3%A9/lib.rs"
+            }"#,
+        )
+    }
+
+    /// Proves the byte-level scanner in `replace` doesn't mistake an escaped quote or backslash
+    /// for the end of a string token, that strings with `\uXXXX`-style escapes still decode to
+    /// the same characters, and that non-string structure is left intact.
+    #[test]
+    fn test_stream_with_replacement_escapes() -> Result<()> {
+        test_stream_with_replacement(
+            r#"{
+                "quoted": "she said \"XYZXYZ\" then left",
+                "backslash": "C:\\XYZXYZ\\dir",
+                "unicode": "caf\u00e9 XYZXYZ \ud83d\ude00",
+                "array": ["XYZXYZ", "unaffected", 5, null, true]
+            }"#,
+            &[("XYZXYZ", "REPLACE")],
+            r#"{
+                "quoted": "she said \"REPLACE\" then left",
+                "backslash": "C:\\REPLACE\\dir",
+                "unicode": "caf\u00e9 REPLACE \ud83d\ude00",
+                "array": ["REPLACE", "unaffected", 5, null, true]
+            }"#,
+        )
+    }
+
+    /// `replace` applies `replacement_map` entries in order via sequential substring
+    /// replacement, so a more specific prefix has to come before a generic one that also
+    /// matches it, or the generic rule consumes the match first and the specific pair never
+    /// fires. `build_replacement_map` relies on this ordering to let cargo-metadata-derived
+    /// package prefixes win over the catch-all `/home` rule.
+    #[test]
+    fn test_stream_with_replacement_specific_prefix_before_generic() -> Result<()> {
+        test_stream_with_replacement(
+            r#"{
+                "path": "/home/user/chromiumos/src/third_party/rust-crate/lib.rs"
+            }"#,
+            &[
+                (
+                    "/home/user/chromiumos/src/third_party/rust-crate",
+                    "/mnt/host/source/src/third_party/rust-crate",
+                ),
+                ("/home/user/chromiumos", "/home"),
+            ],
+            r#"{
+                "path": "/mnt/host/source/src/third_party/rust-crate/lib.rs"
+            }"#,
+        )
+    }
 }